@@ -1,3 +1,11 @@
+use core::comm;
+use core::os;
+use core::rand;
+use core::task;
+
+use std::arc;
+use std::json;
+
 use lmath::funs::common::*;
 
 use geometry::*;
@@ -32,32 +40,189 @@ fn setupScene(s: &Scene) -> SceneParams {
     (aspectRatio, viewLen, horVec, topPixel)
 }
 
-fn intersectNodes(ps: &[Primitive], ray: Vec3<float>, origin: Vec3<float>) -> Option<Intersection> {
-    vec::foldr(ps, None, |x, y| {
-        match move intersect(x, ray, origin) {
-            Some(move newIntersection) => {
-                let (rayLen, _, _) = newIntersection;
-                match y {
-                    Some(oldIntersection) => {
-                        let (oRayLen, _, _): Intersection = oldIntersection;
-                        if oRayLen > rayLen { Some(newIntersection) }
-                        else { Some(oldIntersection) }
-                    }
-                    None => Some(newIntersection)
-                }
-            },
-            None => y
+// Axis-aligned bounding box, used as the node volume in the BVH. Per-
+// primitive boxes come from `bounds` in the geometry module, mirroring the
+// free-function `intersect` used for the actual ray/primitive tests.
+struct AABB {
+    lo: Vec3<float>,
+    hi: Vec3<float>,
+}
+
+impl AABB {
+    fn union(&self, o: &AABB) -> AABB {
+        AABB {
+            lo: Vec3::new(float::min(self.lo.x, o.lo.x), float::min(self.lo.y, o.lo.y), float::min(self.lo.z, o.lo.z)),
+            hi: Vec3::new(float::max(self.hi.x, o.hi.x), float::max(self.hi.y, o.hi.y), float::max(self.hi.z, o.hi.z)),
+        }
+    }
+
+    fn centroid(&self) -> Vec3<float> {
+        self.lo.add_v(&self.hi).mul_t(0.5f)
+    }
+
+    // Slab test. Returns the entry distance along `ray` when the box is hit
+    // in front of the origin within `tmax`, so callers can both reject and
+    // order subtrees by proximity.
+    fn hit(&self, ray: &Vec3<float>, origin: &Vec3<float>, tmax: float) -> Option<float> {
+        let mut tmin = 0.0f;
+        let mut tmax = tmax;
+        for uint::range(0, 3) |axis| {
+            let inv = 1.0f / ray[axis];
+            let mut t0 = (self.lo[axis] - origin[axis]) * inv;
+            let mut t1 = (self.hi[axis] - origin[axis]) * inv;
+            if inv < 0.0f { let tmp = t0; t0 = t1; t1 = tmp; }
+            if t0 > tmin { tmin = t0; }
+            if t1 < tmax { tmax = t1; }
+            if tmax < tmin { return None; }
         }
+        Some(tmin)
+    }
+}
+
+// Bounding-volume hierarchy over primitive indices: interior nodes carry two
+// child boxes, leaves a short index list.
+enum Bvh {
+    Leaf(AABB, ~[uint]),
+    Branch(AABB, ~Bvh, ~Bvh),
+}
+
+fn boundsOf(ps: &[Primitive], idx: &[uint]) -> AABB {
+    vec::foldr(vec::tail(idx), bounds(&ps[idx[0]]), |i, acc| {
+        bounds(&ps[*i]).union(&acc)
     })
 }
 
+// Top-down build: split along the axis of greatest centroid extent at the
+// median, recursing until a node holds at most two primitives.
+fn buildSubtree(ps: &[Primitive], idx: ~[uint]) -> Bvh {
+    let box = boundsOf(ps, idx);
+    if idx.len() <= 2 {
+        return Leaf(box, idx);
+    }
+
+    let centroids = vec::map(idx, |i| bounds(&ps[*i]).centroid());
+    let cbox = vec::foldr(centroids, AABB { lo: centroids[0], hi: centroids[0] }, |c, acc| {
+        AABB { lo: *c, hi: *c }.union(&acc)
+    });
+    let extent = cbox.hi.sub_v(&cbox.lo);
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 }
+               else if extent.y >= extent.z { 1 }
+               else { 2 };
+
+    let mut sorted = copy idx;
+    std::sort::quick_sort(sorted, |a, b| {
+        bounds(&ps[*a]).centroid()[axis] < bounds(&ps[*b]).centroid()[axis]
+    });
+    let mid = sorted.len() / 2;
+    let left = vec::slice(sorted, 0, mid).to_vec();
+    let right = vec::slice(sorted, mid, sorted.len()).to_vec();
+
+    Branch(box, ~buildSubtree(ps, left), ~buildSubtree(ps, right))
+}
+
+fn isFiniteBox(b: &AABB) -> bool {
+    float::is_finite(b.lo.x) && float::is_finite(b.lo.y) && float::is_finite(b.lo.z) &&
+    float::is_finite(b.hi.x) && float::is_finite(b.hi.y) && float::is_finite(b.hi.z)
+}
+
+fn emptyBox() -> AABB {
+    AABB { lo: Vec3::new(float::infinity, float::infinity, float::infinity),
+           hi: Vec3::new(float::neg_infinity, float::neg_infinity, float::neg_infinity) }
+}
+
+fn buildBvh(ps: &[Primitive]) -> Bvh {
+    // Unbounded primitives (e.g. infinite planes) have no finite centroid, so
+    // they can't take part in the centroid median split; collect them
+    // separately into a leaf the slab test always enters.
+    let mut finite = ~[];
+    let mut infinite = ~[];
+    for uint::range(0, ps.len()) |i| {
+        if isFiniteBox(&bounds(&ps[i])) { finite.push(i) } else { infinite.push(i) }
+    }
+
+    let tree = if finite.is_empty() { Leaf(emptyBox(), ~[]) } else { buildSubtree(ps, finite) };
+    if infinite.is_empty() {
+        tree
+    } else {
+        // The branch box is infinite, so traversal always descends into the
+        // always-tested leaf while still pruning the finite subtree normally.
+        let infBox = boundsOf(ps, infinite);
+        Branch(infBox, ~Leaf(infBox, infinite), ~tree)
+    }
+}
+
+fn closer(a: Option<Intersection>, b: Option<Intersection>) -> Option<Intersection> {
+    match (a, b) {
+        (Some(ai), Some(bi)) => {
+            let (aLen, _, _): Intersection = ai;
+            let (bLen, _, _): Intersection = bi;
+            if aLen <= bLen { Some(ai) } else { Some(bi) }
+        }
+        (Some(ai), None) => Some(ai),
+        (None, b) => b,
+    }
+}
+
+// Descend near-child-first, pruning any subtree whose entry distance is
+// already beyond the closest hit found so far.
+fn bvhHit(node: &Bvh, ps: &[Primitive], ray: Vec3<float>, origin: Vec3<float>, best: Option<Intersection>) -> Option<Intersection> {
+    match *node {
+        Leaf(_, ref idx) => {
+            vec::foldr(*idx, best, |i, acc| {
+                closer(intersect(&ps[*i], ray, origin), acc)
+            })
+        }
+        Branch(_, ref l, ref r) => {
+            let bestLen = |o: Option<Intersection>| match o {
+                Some((len, _, _)) => len,
+                None => float::infinity,
+            };
+            let lBox = match *l { Leaf(b, _) | Branch(b, _, _) => b };
+            let rBox = match *r { Leaf(b, _) | Branch(b, _, _) => b };
+            let lHit = lBox.hit(&ray, &origin, bestLen(best));
+            let rHit = rBox.hit(&ray, &origin, bestLen(best));
+
+            match (lHit, rHit) {
+                (None, None) => best,
+                (Some(_), None) => bvhHit(*l, ps, ray, origin, best),
+                (None, Some(_)) => bvhHit(*r, ps, ray, origin, best),
+                (Some(lt), Some(rt)) => {
+                    let (near, far, farT) = if lt <= rt { (l, r, rt) } else { (r, l, lt) };
+                    let best = bvhHit(*near, ps, ray, origin, best);
+                    if farT < bestLen(best) { bvhHit(*far, ps, ray, origin, best) }
+                    else { best }
+                }
+            }
+        }
+    }
+}
+
+fn intersectNodes(bvh: &Bvh, ps: &[Primitive], ray: Vec3<float>, origin: Vec3<float>) -> Option<Intersection> {
+    bvhHit(bvh, ps, ray, origin, None)
+}
+
 #[inline(always)]
 fn vecMult(a: &Vec3<float>, b: &Vec3<float>) -> Vec3<float> {
     Vec3::new(a[0] * b[0], a[1] * b[1], a[2] * b[2])
 }
 
-fn trace(ps: &[Primitive], amb: Vec3<float>, ray: Vec3<float>, origin: Vec3<float>, lights: &[Light]) -> Colour {
-    match move intersectNodes(ps, ray, origin) {
+// R = D - 2(D.N)N, with D and N assumed normalized.
+#[inline(always)]
+fn reflect(d: &Vec3<float>, n: &Vec3<float>) -> Vec3<float> {
+    d.sub_v(&n.mul_t(2.0f * d.dot(n)))
+}
+
+fn clampColour(colours: Vec3<float>) -> Colour {
+    let r = (colours.x * 255.0f).clamp(&(0.0f), &(255.0f));
+    let g = (colours.y * 255.0f).clamp(&(0.0f), &(255.0f));
+    let b = (colours.z * 255.0f).clamp(&(0.0f), &(255.0f));
+
+    (r as u8, g as u8, b as u8)
+}
+
+fn trace(bvh: &Bvh, ps: &[Primitive], amb: Vec3<float>, ray: Vec3<float>, origin: Vec3<float>, lights: &[Light], depth: uint, maxDepth: uint) -> Vec3<float> {
+    if depth > maxDepth { return Vec3::new(0.0f, 0.0f, 0.0f); }
+    match move intersectNodes(bvh, ps, ray, origin) {
         Some((iRayLen, iRay, iP)) => {
             let intersection = origin.add_v(&ray.mul_t(iRayLen));
             let normal = iRay.normalize();
@@ -65,7 +230,7 @@ fn trace(ps: &[Primitive], amb: Vec3<float>, ray: Vec3<float>, origin: Vec3<floa
             let mat = iP.mat;
             let lightIntersections = vec::filter(vec::map(lights, |light| {
                 let shadowRay = light.pos.sub_v(&intersection);
-                (*light, intersectNodes(ps, shadowRay, intersection))
+                (*light, intersectNodes(bvh, ps, shadowRay, intersection))
             }), |r| {
                 let (_, r) = *r;
                 option::is_none(&r)
@@ -102,19 +267,164 @@ fn trace(ps: &[Primitive], amb: Vec3<float>, ray: Vec3<float>, origin: Vec3<floa
                 specularColour.add_v(&r)
             });
 
-            let colours = diffuse.add_v(&specular.add_v(&vecMult(&amb, &mat.diffuse)));
-            let r = (colours.x * 255.0f).clamp(&(0.0f), &(255.0f));
-            let g = (colours.y * 255.0f).clamp(&(0.0f), &(255.0f));
-            let b = (colours.z * 255.0f).clamp(&(0.0f), &(255.0f));
+            let local = diffuse.add_v(&specular.add_v(&vecMult(&amb, &mat.diffuse)));
+
+            // Secondary rays: mirror reflection and, for transparent
+            // materials, refraction through the surface. The two are mixed
+            // with a Schlick-approximated Fresnel term and weighted by the
+            // material's reflectivity/transparency coefficients.
+            if mat.reflectivity <= EPSILON && mat.transparency <= EPSILON {
+                return local;
+            }
+
+            // cosθi against the outward-facing normal; flip the normal and
+            // swap the media when the ray leaves the surface.
+            let cosi = normalizedRay.dot(&normal);
+            let (outward, cosi, eta) =
+                if cosi < 0.0f {
+                    (normal, -cosi, 1.0f / mat.ior)
+                } else {
+                    (normal.mul_t(-1.0f), cosi, mat.ior)
+                };
+            let bias = outward.mul_t(EPSILON);
+
+            let schlick5 = float::pow((1.0f - cosi) as libc::c_double, 5.0 as libc::c_double) as float;
+
+            let reflectedRay = reflect(&normalizedRay, &outward);
+            let reflected = trace(bvh, ps, amb, reflectedRay, intersection.add_v(&bias), lights, depth + 1, maxDepth);
+
+            if mat.transparency > EPSILON {
+                // For dielectrics the base reflectance comes from the index of
+                // refraction, so grazing angles reflect and head-on refracts.
+                let r0 = { let t = (1.0f - mat.ior) / (1.0f + mat.ior); t * t };
+                let fresnel = r0 + (1.0f - r0) * schlick5;
+                let cost2 = 1.0f - eta * eta * (1.0f - cosi * cosi);
+                if cost2 < 0.0f {
+                    // Total internal reflection: all energy reflects.
+                    return local.mul_t(1.0f - mat.transparency)
+                                .add_v(&reflected.mul_t(mat.transparency));
+                }
+                let refractedRay = normalizedRay.mul_t(eta)
+                                    .add_v(&outward.mul_t(eta * cosi - float::sqrt(cost2)));
+                let refracted = trace(bvh, ps, amb, refractedRay, intersection.sub_v(&bias), lights, depth + 1, maxDepth);
+                let secondary = reflected.mul_t(fresnel).add_v(&refracted.mul_t(1.0f - fresnel));
+                local.mul_t(1.0f - mat.transparency).add_v(&secondary.mul_t(mat.transparency))
+            } else {
+                // Opaque mirror: `reflectivity` is the base reflectance (R0),
+                // with a Schlick ramp to full reflection at grazing angles, so
+                // a mirror facing the camera still reflects at R0.
+                let weight = mat.reflectivity + (1.0f - mat.reflectivity) * schlick5;
+                local.mul_t(1.0f - weight).add_v(&reflected.mul_t(weight))
+            }
+        },
+        None => Vec3::new(26.0f / 255.0f, 26.0f / 255.0f, 26.0f / 255.0f)
+    }
+}
+
+fn doTrace(s: &Scene, bvh: &Bvh, params: SceneParams, posn: Pixel) -> Colour {
+    let (aspectRatio, _viewLen, horVec, topPixel) = params;
+    let (x, y) = posn;
+    let currentPixel = topPixel
+                        .add_v(&horVec.mul_t(aspectRatio * (x as float)))
+                        .add_v(&s.up.mul_t(y as float));
+
+    // Shoot an NxN grid of rays across the pixel footprint and average the
+    // results in float space. A factor of 1 offsets by (0,0), reproducing
+    // the single centre ray exactly.
+    let n = uint::max(1, s.supersample);
+    let horStep = horVec.mul_t(aspectRatio);
+    let lensUp = s.up.normalize();
+    // Only the depth-of-field path draws random samples, so the pinhole path
+    // never pays for an RNG.
+    let rng = if s.aperture > 0.0f { Some(rand::Rng()) } else { None };
+    let mut accum = Vec3::new(0.0f, 0.0f, 0.0f);
+    for uint::range(0, n) |sy| {
+        for uint::range(0, n) |sx| {
+            let dx = ((sx as float) + 0.5f) / (n as float) - 0.5f;
+            let dy = ((sy as float) + 0.5f) / (n as float) - 0.5f;
+            let sample = currentPixel.add_v(&horStep.mul_t(dx)).add_v(&s.up.mul_t(dy));
+            let pinhole = sample.sub_v(&s.camera);
+
+            let (origin, ray) =
+                if s.aperture > 0.0f {
+                    // Thin lens: jitter the origin over the aperture disk and
+                    // re-aim at where the pinhole ray crosses the focal plane,
+                    // so only that plane stays sharp.
+                    let rng = rng.get();
+                    let focalPoint = s.camera.add_v(&pinhole.mul_t(s.focal_distance));
+                    let r = s.aperture * float::sqrt(rng.gen_float());
+                    let theta = 2.0f * float::consts::pi * rng.gen_float();
+                    let origin = s.camera
+                                    .add_v(&horVec.mul_t(r * float::cos(theta)))
+                                    .add_v(&lensUp.mul_t(r * float::sin(theta)));
+                    (origin, focalPoint.sub_v(&origin))
+                } else {
+                    (s.camera, pinhole)
+                };
+            accum = accum.add_v(&trace(bvh, s.primitives, s.ambient, ray, origin, s.lights, 0, s.max_depth));
+        }
+    }
+
+    clampColour(accum.mul_t(1.0f / ((n * n) as float)))
+}
+
+// Build a unit sample direction on the cosine-weighted hemisphere around
+// `normal`, expressed in a tangent frame raised from the normal.
+fn cosineSampleHemisphere(normal: &Vec3<float>, rng: @rand::Rng) -> Vec3<float> {
+    let r1 = rng.gen_float();
+    let r2 = rng.gen_float();
+    let theta = float::acos(float::sqrt(1.0f - r1));
+    let phi = 2.0f * float::consts::pi * r2;
+    let x = float::sin(theta) * float::cos(phi);
+    let y = float::sin(theta) * float::sin(phi);
+    let z = float::cos(theta);
+
+    let w = *normal;
+    let a = if float::abs(w.x) > 0.9f { Vec3::new(0.0f, 1.0f, 0.0f) }
+            else { Vec3::new(1.0f, 0.0f, 0.0f) };
+    let u = a.cross(&w).normalize();
+    let v = w.cross(&u);
+
+    u.mul_t(x).add_v(&v.mul_t(y)).add_v(&w.mul_t(z))
+}
+
+// Unbiased global-illumination estimate for a single ray. Lights are just
+// emissive primitives; the path is extended by cosine-weighted hemisphere
+// sampling and terminated by Russian roulette on the surface albedo.
+fn pathTrace(bvh: &Bvh, ps: &[Primitive], ray: Vec3<float>, origin: Vec3<float>, rng: @rand::Rng, depth: uint, maxDepth: uint) -> Vec3<float> {
+    match move intersectNodes(bvh, ps, ray, origin) {
+        Some((iRayLen, iRay, iP)) => {
+            let intersection = origin.add_v(&ray.mul_t(iRayLen));
+            let geomNormal = iRay.normalize();
+            let normal = if ray.dot(&geomNormal) < 0.0f { geomNormal } else { geomNormal.mul_t(-1.0f) };
+            let mat = iP.mat;
+
+            let albedo = mat.diffuse;
+            // Roulette continuation probability is the largest albedo
+            // component, capped at 1; it is applied once depth reaches
+            // maxDepth. A zero or NaN albedo ends the path at this surface.
+            let maxComp = float::max(albedo.x, float::max(albedo.y, albedo.z));
+            let p = if float::is_finite(maxComp) && maxComp > EPSILON { float::min(maxComp, 1.0f) }
+                    else { return mat.emission; };
 
-            (r as u8, g as u8, b as u8)
+            let throughput =
+                if depth >= maxDepth {
+                    if rng.gen_float() >= p { return mat.emission; }
+                    albedo.mul_t(1.0f / p)
+                } else {
+                    albedo
+                };
 
+            let newDir = cosineSampleHemisphere(&normal, rng);
+            let incoming = pathTrace(bvh, ps, newDir, intersection.add_v(&normal.mul_t(EPSILON)), rng, depth + 1, maxDepth);
+
+            mat.emission.add_v(&vecMult(&throughput, &incoming))
         },
-        None => (26, 26, 26)
+        None => Vec3::new(0.0f, 0.0f, 0.0f)
     }
 }
 
-fn doTrace(s: &Scene, params: SceneParams, posn: Pixel) -> Colour {
+fn doPathTrace(s: &Scene, bvh: &Bvh, params: SceneParams, posn: Pixel, rng: @rand::Rng) -> Colour {
     let (aspectRatio, _viewLen, horVec, topPixel) = params;
     let (x, y) = posn;
     let currentPixel = topPixel
@@ -122,22 +432,332 @@ fn doTrace(s: &Scene, params: SceneParams, posn: Pixel) -> Colour {
                         .add_v(&s.up.mul_t(y as float));
     let ray = currentPixel.sub_v(&s.camera);
 
-    trace(s.primitives, s.ambient, ray, s.camera, s.lights)
+    let mut accum = Vec3::new(0.0f, 0.0f, 0.0f);
+    for uint::range(0, s.passes) |_| {
+        accum = accum.add_v(&pathTrace(bvh, s.primitives, ray, s.camera, rng, 0, s.max_depth));
+    }
+    clampColour(accum.mul_t(1.0f / (s.passes as float)))
 }
 
-fn render(s: &Scene) -> ~[~[Colour]] {
+fn pathRender(s: &Scene) -> ~[~[Colour]] {
     let params = setupScene(s);
+    let bvh = buildBvh(s.primitives);
+    let rng = rand::Rng();
     vec::map(makePixels(s.width, s.height), |column| {
         vec::map(*column, |pix| {
-            doTrace(s, params, *pix)
+            doPathTrace(s, &bvh, params, *pix, rng)
         })
     })
 }
 
+fn render(s: &Scene) -> ~[~[Colour]] {
+    let params = setupScene(s);
+    let bvh = buildBvh(s.primitives);
+
+    let rows = makePixels(s.width, s.height);
+    let nRows = rows.len();
+    // `threads == 0` means "use every core"; otherwise honour the cap.
+    let nThreads = uint::max(1, if s.threads == 0 { os::num_cpus() } else { s.threads });
+
+    // Tracing is read-only over the scene, so the whole bundle is shared
+    // immutably and each worker writes only its own output band.
+    let shared = arc::ARC((copy *s, bvh, params));
+
+    // Split the image into contiguous row bands, one per worker, and stream
+    // each band back tagged with its start row so the image is reassembled
+    // top-to-bottom regardless of completion order.
+    let chunk = (nRows + nThreads - 1) / nThreads;
+    let (port, chan) = comm::stream();
+    let chan = comm::SharedChan(chan);
+
+    let mut spawned = 0;
+    let mut lo = 0;
+    while lo < nRows {
+        let hi = uint::min(lo + chunk, nRows);
+        let band = vec::slice(rows, lo, hi).to_vec();
+        let shared = arc::clone(&shared);
+        let chan = chan.clone();
+        let start = lo;
+        do task::spawn |move band, move shared, move chan| {
+            let &(ref scene, ref bvh, params) = arc::get(&shared);
+            let out = vec::map(band, |column| {
+                vec::map(*column, |pix| doTrace(scene, bvh, params, *pix))
+            });
+            chan.send((start, move out));
+        }
+        spawned += 1;
+        lo = hi;
+    }
+
+    let mut result = vec::from_elem(nRows, ~[]);
+    for uint::range(0, spawned) |_| {
+        let (start, band) = port.recv();
+        for vec::eachi(band) |i, row| {
+            result[start + i] = copy *row;
+        }
+    }
+    result
+}
+
+// --- JSON scene loading -------------------------------------------------
+//
+// Every accessor names the field it was reading so a malformed document
+// produces an actionable "field `x`: ..." message rather than a panic.
+
+fn jField<'a>(o: &'a json::Object, name: &str) -> Result<&'a json::Json, ~str> {
+    match o.find(&name.to_owned()) {
+        Some(v) => Ok(v),
+        None => Err(fmt!("missing field `%s`", name)),
+    }
+}
+
+fn jFloat(j: &json::Json, name: &str) -> Result<float, ~str> {
+    match *j {
+        json::Number(n) => Ok(n as float),
+        _ => Err(fmt!("field `%s`: expected a number", name)),
+    }
+}
+
+fn jUint(j: &json::Json, name: &str) -> Result<uint, ~str> {
+    match *j {
+        json::Number(n) if n >= 0.0 => Ok(n as uint),
+        _ => Err(fmt!("field `%s`: expected a non-negative integer", name)),
+    }
+}
+
+fn jVec3(j: &json::Json, name: &str) -> Result<Vec3<float>, ~str> {
+    match *j {
+        json::List(ref xs) if xs.len() == 3 => {
+            let x = match jFloat(&xs[0], name) { Ok(v) => v, Err(e) => return Err(e) };
+            let y = match jFloat(&xs[1], name) { Ok(v) => v, Err(e) => return Err(e) };
+            let z = match jFloat(&xs[2], name) { Ok(v) => v, Err(e) => return Err(e) };
+            Ok(Vec3::new(x, y, z))
+        }
+        _ => Err(fmt!("field `%s`: expected a [x, y, z] list", name)),
+    }
+}
+
+// Optional scalar with a default, so scene files can omit render tunables.
+fn jUintOr(o: &json::Object, name: &str, default: uint) -> Result<uint, ~str> {
+    match o.find(&name.to_owned()) {
+        Some(v) => jUint(v, name),
+        None => Ok(default),
+    }
+}
+
+fn jBoolOr(o: &json::Object, name: &str, default: bool) -> Result<bool, ~str> {
+    match o.find(&name.to_owned()) {
+        Some(&json::Boolean(b)) => Ok(b),
+        Some(_) => Err(fmt!("field `%s`: expected a boolean", name)),
+        None => Ok(default),
+    }
+}
+
+fn parseMaterial(j: &json::Json) -> Result<Material, ~str> {
+    let o = match *j { json::Object(ref o) => o, _ => return Err(~"material: expected an object") };
+    let diffuse  = match jField(*o, "diffuse")  { Ok(v) => match jVec3(v, "diffuse")  { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let specular = match jField(*o, "specular") { Ok(v) => match jVec3(v, "specular") { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let shininess = match jField(*o, "shininess") { Ok(v) => match jFloat(v, "shininess") { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    // Secondary-ray and emission terms default to a plain matte surface.
+    let reflectivity = match o.find(&~"reflectivity") { Some(v) => match jFloat(v, "reflectivity") { Ok(v) => v, Err(e) => return Err(e) }, None => 0.0f };
+    let transparency = match o.find(&~"transparency") { Some(v) => match jFloat(v, "transparency") { Ok(v) => v, Err(e) => return Err(e) }, None => 0.0f };
+    let ior = match o.find(&~"ior") { Some(v) => match jFloat(v, "ior") { Ok(v) => v, Err(e) => return Err(e) }, None => 1.0f };
+    let emission = match o.find(&~"emission") { Some(v) => match jVec3(v, "emission") { Ok(v) => v, Err(e) => return Err(e) }, None => Vec3::new(0.0f, 0.0f, 0.0f) };
+
+    Ok(Material { diffuse: diffuse, specular: specular, shininess: shininess,
+                  reflectivity: reflectivity, transparency: transparency,
+                  ior: ior, emission: emission })
+}
+
+fn parsePrimitive(j: &json::Json) -> Result<Primitive, ~str> {
+    let o = match *j { json::Object(ref o) => o, _ => return Err(~"primitive: expected an object") };
+    let mat = match jField(*o, "material") { Ok(v) => match parseMaterial(v) { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let kind = match jField(*o, "type") {
+        Ok(&json::String(ref s)) => copy *s,
+        Ok(_) => return Err(~"primitive field `type`: expected a string"),
+        Err(e) => return Err(e),
+    };
+    match kind {
+        ~"sphere" => {
+            let center = match jField(*o, "center") { Ok(v) => match jVec3(v, "center") { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+            let radius = match jField(*o, "radius") { Ok(v) => match jFloat(v, "radius") { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+            Ok(newSphere(center, radius, mat))
+        }
+        ~"plane" => {
+            let point  = match jField(*o, "point")  { Ok(v) => match jVec3(v, "point")  { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+            let normal = match jField(*o, "normal") { Ok(v) => match jVec3(v, "normal") { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+            Ok(newPlane(point, normal, mat))
+        }
+        _ => Err(fmt!("primitive field `type`: unknown kind `%s`", kind)),
+    }
+}
+
+fn parseLight(j: &json::Json) -> Result<Light, ~str> {
+    let o = match *j { json::Object(ref o) => o, _ => return Err(~"light: expected an object") };
+    let pos    = match jField(*o, "pos")    { Ok(v) => match jVec3(v, "pos")    { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let colour = match jField(*o, "colour") { Ok(v) => match jVec3(v, "colour") { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    Ok(Light { pos: pos, colour: colour })
+}
+
+fn parseList<T>(j: &json::Json, name: &str, f: &fn(&json::Json) -> Result<T, ~str>) -> Result<~[T], ~str> {
+    match *j {
+        json::List(ref xs) => {
+            let mut out = ~[];
+            for xs.each |x| {
+                match f(x) {
+                    Ok(v) => out.push(v),
+                    Err(e) => return Err(fmt!("%s: %s", name, e)),
+                }
+            }
+            Ok(out)
+        }
+        _ => Err(fmt!("field `%s`: expected a list", name)),
+    }
+}
+
+// Read Wavefront OBJ vertex/face data into a list of triangle primitives
+// sharing `mat`. Only `v` and `f` records are used; face tokens may be
+// `v`, `v/vt`, or `v/vt/vn` — we take the leading vertex index. Shading
+// normals are accumulated per vertex from the adjacent faces so meshes
+// render smooth rather than faceted.
+fn loadObj(path: &str, mat: Material) -> Result<~[Primitive], ~str> {
+    let src = match io::read_whole_file_str(&Path(path)) {
+        Ok(s) => s,
+        Err(e) => return Err(fmt!("could not read obj `%s`: %s", path, e)),
+    };
+
+    let mut verts: ~[Vec3<float>] = ~[];
+    let mut faces: ~[(uint, uint, uint)] = ~[];
+
+    for src.each_line |line| {
+        let toks = str::words(line);
+        if toks.len() == 0 { loop; }
+        match toks[0] {
+            ~"v" if toks.len() >= 4 => {
+                let x = match float::from_str(toks[1]) { Some(n) => n, None => return Err(fmt!("obj `%s`: bad vertex", path)) };
+                let y = match float::from_str(toks[2]) { Some(n) => n, None => return Err(fmt!("obj `%s`: bad vertex", path)) };
+                let z = match float::from_str(toks[3]) { Some(n) => n, None => return Err(fmt!("obj `%s`: bad vertex", path)) };
+                verts.push(Vec3::new(x, y, z));
+            }
+            ~"f" if toks.len() >= 4 => {
+                let idx = |tok: &str| -> Result<uint, ~str> {
+                    let head = str::split_char(tok, '/')[0];
+                    match uint::from_str(head) {
+                        Some(n) if n >= 1 && n <= verts.len() => Ok(n - 1),
+                        _ => Err(fmt!("obj `%s`: bad face index `%s`", path, tok)),
+                    }
+                };
+                // Triangulate the polygon as a fan around its first vertex.
+                let a = match idx(toks[1]) { Ok(n) => n, Err(e) => return Err(e) };
+                for uint::range(2, toks.len() - 1) |k| {
+                    let b = match idx(toks[k])     { Ok(n) => n, Err(e) => return Err(e) };
+                    let c = match idx(toks[k + 1]) { Ok(n) => n, Err(e) => return Err(e) };
+                    faces.push((a, b, c));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Smooth shading normals: area-weighted sum of adjacent face normals.
+    let mut normals = vec::from_elem(verts.len(), Vec3::new(0.0f, 0.0f, 0.0f));
+    for faces.each |&(a, b, c)| {
+        let fn_ = verts[b].sub_v(&verts[a]).cross(&verts[c].sub_v(&verts[a]));
+        normals[a] = normals[a].add_v(&fn_);
+        normals[b] = normals[b].add_v(&fn_);
+        normals[c] = normals[c].add_v(&fn_);
+    }
+    for uint::range(0, normals.len()) |i| {
+        normals[i] = normals[i].normalize();
+    }
+
+    Ok(vec::map(faces, |&(a, b, c)| {
+        newTriangle(verts[a], verts[b], verts[c],
+                    normals[a], normals[b], normals[c], mat)
+    }))
+}
+
+// A `mesh` entry expands to many triangle primitives; everything else is a
+// single analytic primitive.
+fn parsePrimitives(j: &json::Json) -> Result<~[Primitive], ~str> {
+    let xs = match *j { json::List(ref xs) => xs, _ => return Err(~"field `primitives`: expected a list") };
+    let mut out = ~[];
+    for xs.each |x| {
+        let o = match *x { json::Object(ref o) => o, _ => return Err(~"primitives: expected an object") };
+        let isMesh = match o.find(&~"type") { Some(&json::String(ref s)) => *s == ~"mesh", _ => false };
+        if isMesh {
+            let mat = match jField(*o, "material") { Ok(v) => match parseMaterial(v) { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+            let path = match jField(*o, "obj") { Ok(&json::String(ref s)) => copy *s, Ok(_) => return Err(~"mesh field `obj`: expected a string"), Err(e) => return Err(e) };
+            match loadObj(path, mat) { Ok(tris) => out.push_all_move(tris), Err(e) => return Err(e) }
+        } else {
+            match parsePrimitive(x) { Ok(p) => out.push(p), Err(e) => return Err(fmt!("primitives: %s", e)) }
+        }
+    }
+    Ok(out)
+}
+
+fn parseScene(j: &json::Json) -> Result<Scene, ~str> {
+    let o = match *j { json::Object(ref o) => o, _ => return Err(~"scene: expected a top-level object") };
+
+    let width  = match jField(*o, "width")  { Ok(v) => match jUint(v, "width")  { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let height = match jField(*o, "height") { Ok(v) => match jUint(v, "height") { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let fov    = match jField(*o, "fov")    { Ok(v) => match jFloat(v, "fov")   { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let camera = match jField(*o, "camera") { Ok(v) => match jVec3(v, "camera") { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let view   = match jField(*o, "view")   { Ok(v) => match jVec3(v, "view")   { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let up     = match jField(*o, "up")     { Ok(v) => match jVec3(v, "up")     { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let ambient = match jField(*o, "ambient") { Ok(v) => match jVec3(v, "ambient") { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let primitives = match jField(*o, "primitives") { Ok(v) => match parsePrimitives(v) { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+    let lights = match jField(*o, "lights") { Ok(v) => match parseList(v, "lights", parseLight) { Ok(v) => v, Err(e) => return Err(e) }, Err(e) => return Err(e) };
+
+    let max_depth  = match jUintOr(*o, "max_depth", 5)   { Ok(v) => v, Err(e) => return Err(e) };
+    let passes     = match jUintOr(*o, "passes", 1)      { Ok(v) => v, Err(e) => return Err(e) };
+    let supersample = match jUintOr(*o, "supersample", 1) { Ok(v) => v, Err(e) => return Err(e) };
+    let threads    = match jUintOr(*o, "threads", 0)     { Ok(v) => v, Err(e) => return Err(e) };
+
+    // Pinhole camera by default; a positive aperture enables depth of field.
+    let aperture = match o.find(&~"aperture") { Some(v) => match jFloat(v, "aperture") { Ok(v) => v, Err(e) => return Err(e) }, None => 0.0f };
+    let focal_distance = match o.find(&~"focal_distance") { Some(v) => match jFloat(v, "focal_distance") { Ok(v) => v, Err(e) => return Err(e) }, None => 1.0f };
+
+    // Whitted tracer by default; opt into the Monte-Carlo path tracer.
+    let pathtrace = match jBoolOr(*o, "pathtrace", false) { Ok(v) => v, Err(e) => return Err(e) };
+
+    Ok(Scene { width: width, height: height, fov: fov, camera: camera,
+               view: view, up: up, ambient: ambient, primitives: primitives,
+               lights: lights, max_depth: max_depth, passes: passes,
+               supersample: supersample, threads: threads,
+               aperture: aperture, focal_distance: focal_distance,
+               pathtrace: pathtrace })
+}
+
+fn loadScene(path: &str) -> Result<Scene, ~str> {
+    let src = match io::read_whole_file_str(&Path(path)) {
+        Ok(s) => s,
+        Err(e) => return Err(fmt!("could not read `%s`: %s", path, e)),
+    };
+    match json::from_str(src) {
+        Ok(j) => parseScene(&j),
+        Err(e) => Err(fmt!("invalid JSON: %s", e.to_str())),
+    }
+}
+
 fn main() {
 
-    let refScene = getRefScene();
-    let r = render(&refScene);
+    let args = os::args();
+    let scene = if args.len() > 1 {
+        match loadScene(args[1]) {
+            Ok(s) => s,
+            Err(e) => {
+                io::stderr().write_line(fmt!("rray: %s", e));
+                os::set_exit_status(1);
+                return;
+            }
+        }
+    } else {
+        getRefScene()
+    };
+
+    let refScene = scene;
+    let r = if refScene.pathtrace { pathRender(&refScene) } else { render(&refScene) };
 
     io::println("P3");
     io::println(#fmt("%u %u", refScene.width, refScene.height));